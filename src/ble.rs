@@ -1,28 +1,57 @@
 // Copyright © 2023 Daniele Tricoli <eriol@mornie.org>
 // SPDX-License-Identifier: BSD-3-Clause
 
+use std::collections::BTreeMap;
 use std::error::Error;
 use std::str;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::mpsc::Receiver;
+use std::sync::Arc;
 use std::time::Duration;
 
 use btleplug::api::{
-    BDAddr, Central, CentralEvent, Manager as _, Peripheral as _, ScanFilter, WriteType,
+    BDAddr, Central, CentralEvent, Characteristic, Manager as _, Peripheral as _, ScanFilter,
+    WriteType,
 };
 use btleplug::platform::{Adapter, Manager, Peripheral};
 use clap::{crate_name, crate_version};
 use console::Term;
 use futures::stream::StreamExt;
+use tokio::sync::Mutex;
 use tokio::time;
 use uuid::Uuid;
 
-use crate::utils::{get_stdin_line_channel, progress_bar};
+use crate::utils::{get_stdin_line_channel, mtu_chunks, progress_bar, LineBuffer};
 
-const NORDIC_UART_SERVICE_UUID: Uuid = Uuid::from_u128(0x6e400001_b5a3_f393_e0a9_e50e24dcca9e);
-const NORDIC_UART_TX_CHAR_UUID: Uuid = Uuid::from_u128(0x6e400002_b5a3_f393_e0a9_e50e24dcca9e);
-const NORDIC_UART_RX_CHAR_UUID: Uuid = Uuid::from_u128(0x6e400003_b5a3_f393_e0a9_e50e24dcca9e);
+pub(crate) const NORDIC_UART_SERVICE_UUID: Uuid =
+    Uuid::from_u128(0x6e400001_b5a3_f393_e0a9_e50e24dcca9e);
+pub(crate) const NORDIC_UART_TX_CHAR_UUID: Uuid =
+    Uuid::from_u128(0x6e400002_b5a3_f393_e0a9_e50e24dcca9e);
+pub(crate) const NORDIC_UART_RX_CHAR_UUID: Uuid =
+    Uuid::from_u128(0x6e400003_b5a3_f393_e0a9_e50e24dcca9e);
 const INVALID_RSSI: i16 = i16::MIN;
 
+/// The service and characteristic UUIDs that make up a serial-over-GATT
+/// profile. Defaults to the Nordic UART Service, but `scan`, `send`, `repl`
+/// and `write_ble` only ever go through this so other profiles (e.g.
+/// meshtastic's TORADIO/FROMRADIO/FROMNUM) can be driven by the same code.
+#[derive(Debug, Clone, Copy)]
+pub struct GattProfile {
+    pub service_uuid: Uuid,
+    pub tx_char_uuid: Uuid,
+    pub rx_char_uuid: Uuid,
+}
+
+impl Default for GattProfile {
+    fn default() -> Self {
+        Self {
+            service_uuid: NORDIC_UART_SERVICE_UUID,
+            tx_char_uuid: NORDIC_UART_TX_CHAR_UUID,
+            rx_char_uuid: NORDIC_UART_RX_CHAR_UUID,
+        }
+    }
+}
+
 /// A result from Bluetooth scan.
 #[derive(Debug, Default, Clone)]
 pub struct ScanResult {
@@ -31,61 +60,99 @@ pub struct ScanResult {
     pub rssi: i16,
 }
 
-pub async fn scan(adapter_name: String, scan_time: u64) -> Result<Vec<ScanResult>, Box<dyn Error>> {
+pub async fn scan(
+    adapter_name: String,
+    scan_time: u64,
+    profile: GattProfile,
+) -> Result<Vec<ScanResult>, Box<dyn Error>> {
     let scan_time = Duration::from_secs(scan_time);
     let manager = Manager::new().await?;
     let adapter = get_adapter_by_name(&manager, adapter_name).await?;
+
+    let mut events = adapter.events().await?;
     adapter
-        // We don't specify a scan filter because the paired devices are showed
-        // anyway.
-        .start_scan(ScanFilter::default())
+        .start_scan(ScanFilter {
+            services: vec![profile.service_uuid],
+        })
         .await
         .expect("An error occurred while scanning for devices");
 
-    progress_bar(scan_time);
-    time::sleep(scan_time).await;
+    let found = Arc::new(AtomicUsize::new(0));
+    progress_bar(scan_time, found.clone());
 
-    let mut results: Vec<ScanResult> = Vec::new();
-    let peripherals = adapter.peripherals().await?;
-    if peripherals.is_empty() {
-        eprintln!("No devices found");
-    } else {
-        for peripheral in peripherals.iter() {
-            let properties = peripheral.properties().await?;
-            let services = &properties
-                .as_ref()
-                .ok_or_else(|| "Error discovering services".to_string())?
-                .services;
-            if !services.contains(&NORDIC_UART_SERVICE_UUID) {
-                continue;
+    let mut results: BTreeMap<BDAddr, ScanResult> = BTreeMap::new();
+    let deadline = time::sleep(scan_time);
+    tokio::pin!(deadline);
+
+    loop {
+        tokio::select! {
+            _ = &mut deadline => break,
+            event = events.next() => {
+                match event {
+                    Some(CentralEvent::DeviceDiscovered(id)) | Some(CentralEvent::DeviceUpdated(id)) => {
+                        let peripheral = adapter.peripheral(&id).await?;
+                        if let Some(result) = scan_result(&profile, &peripheral).await? {
+                            results.insert(result.address, result);
+                            found.store(results.len(), Ordering::Relaxed);
+                        }
+                    }
+                    None => break,
+                    _ => {}
+                }
             }
-            let address = properties
-                .as_ref()
-                .ok_or_else(|| "Error reading device address".to_string())?
-                .address;
-            let rssi = properties
-                .as_ref()
-                .ok_or_else(|| "Error reading device rssi".to_string())?
-                .rssi
-                .unwrap_or(INVALID_RSSI);
-            let local_name = properties
-                .as_ref()
-                .ok_or_else(|| "Error reading device name".to_string())?
-                .local_name
-                .clone()
-                .unwrap_or(address.to_string());
-            results.push(ScanResult {
-                address,
-                local_name,
-                rssi,
-            });
         }
     }
+    adapter.stop_scan().await.ok();
+
+    if results.is_empty() {
+        eprintln!("No devices found");
+    }
 
+    let mut results: Vec<ScanResult> = results.into_values().collect();
     results.sort_by(|a, b| b.rssi.cmp(&a.rssi));
     Ok(results)
 }
 
+/// Build a `ScanResult` out of a discovered peripheral's advertised
+/// properties, or `None` if it doesn't advertise `profile`'s service.
+async fn scan_result(
+    profile: &GattProfile,
+    peripheral: &Peripheral,
+) -> Result<Option<ScanResult>, Box<dyn Error>> {
+    let properties = match peripheral.properties().await? {
+        Some(properties) => properties,
+        None => return Ok(None),
+    };
+    if !properties.services.contains(&profile.service_uuid) {
+        return Ok(None);
+    }
+
+    let address = properties.address;
+    let rssi = properties.rssi.unwrap_or(INVALID_RSSI);
+    let local_name = properties.local_name.unwrap_or(address.to_string());
+    Ok(Some(ScanResult {
+        address,
+        local_name,
+        rssi,
+    }))
+}
+
+/// Write `data` to `char_`, splitting it into MTU-sized fragments so writes
+/// longer than the ATT MTU aren't silently truncated or rejected. The Nordic
+/// UART profile is a byte stream with no reassembly of its own, so the
+/// fragments must be written sequentially and in order.
+async fn write_chunked(
+    device: &Peripheral,
+    char_: &Characteristic,
+    data: &[u8],
+    write_type: WriteType,
+) -> Result<(), Box<dyn Error>> {
+    for chunk in mtu_chunks(data) {
+        device.write(char_, chunk, write_type).await?;
+    }
+    Ok(())
+}
+
 async fn get_adapter_by_name(manager: &Manager, name: String) -> Result<Adapter, Box<dyn Error>> {
     let adapters = manager.adapters().await?;
     for adapter in adapters {
@@ -98,12 +165,9 @@ async fn get_adapter_by_name(manager: &Manager, name: String) -> Result<Adapter,
 }
 
 async fn find_device_by_address(
-    adapter_name: String,
-    address: String,
+    adapter: &Adapter,
+    address: &str,
 ) -> Result<Peripheral, Box<dyn Error>> {
-    let manager = Manager::new().await?;
-    let adapter = get_adapter_by_name(&manager, adapter_name).await?;
-
     let mut events = adapter.events().await?;
     adapter.start_scan(ScanFilter::default()).await?;
 
@@ -133,23 +197,26 @@ pub async fn send(
     adapter_name: String,
     address: String,
     text: String,
+    profile: GattProfile,
 ) -> Result<(), Box<dyn Error>> {
     let is_a_command = text.starts_with("!");
 
-    let device = find_device_by_address(adapter_name, address).await?;
+    let manager = Manager::new().await?;
+    let adapter = get_adapter_by_name(&manager, adapter_name).await?;
+    let device = find_device_by_address(&adapter, &address).await?;
     device.connect().await?;
     if device.is_connected().await? {
         device.discover_services().await?;
         let chars = device.characteristics();
         let tx_char = chars
             .iter()
-            .find(|c| c.uuid == NORDIC_UART_TX_CHAR_UUID)
+            .find(|c| c.uuid == profile.tx_char_uuid)
             .ok_or("Unable to find TX characteric")?;
 
         if is_a_command {
             let rx_char = chars
                 .iter()
-                .find(|c| c.uuid == NORDIC_UART_RX_CHAR_UUID)
+                .find(|c| c.uuid == profile.rx_char_uuid)
                 .ok_or("Unable to find RX characteric")?;
             device.subscribe(&rx_char).await?;
         }
@@ -158,7 +225,7 @@ pub async fn send(
         } else {
             WriteType::WithoutResponse
         };
-        device.write(&tx_char, text.as_bytes(), type_).await?;
+        write_chunked(&device, tx_char, text.as_bytes(), type_).await?;
         if is_a_command {
             let mut notification_stream = device.notifications().await?.take(1);
             while let Some(data) = notification_stream.next().await {
@@ -173,44 +240,100 @@ pub async fn send(
     Ok(())
 }
 
-pub async fn repl(adapter_name: String, address: String) -> Result<(), Box<dyn Error>> {
+pub async fn repl(
+    adapter_name: String,
+    address: String,
+    reconnect: bool,
+    retry_interval: u64,
+    profile: GattProfile,
+) -> Result<(), Box<dyn Error>> {
     let term = Term::stdout();
     term.write_line(format!("{} {}", crate_name!(), crate_version!()).as_str())?;
-    term.write_line(format!("Connecting to... {}", address).as_str())?;
-    let device = find_device_by_address(adapter_name, address).await?;
-    device.connect().await?;
-    if device.is_connected().await? {
-        term.write_line("Connected. Type quit() to exit.")?;
-        device.discover_services().await?;
 
-        let line_channel = get_stdin_line_channel();
-        tokio::spawn(write_ble(device.clone(), line_channel));
+    let manager = Manager::new().await?;
+    let adapter = get_adapter_by_name(&manager, adapter_name).await?;
+
+    // The stdin reader and the writer task it feeds live for the whole repl
+    // session: reconnecting only swaps which peripheral `write_ble` is
+    // currently writing to, so lines typed during a reconnect aren't lost.
+    let line_channel = get_stdin_line_channel();
+    let current_device: Arc<Mutex<Option<Peripheral>>> = Arc::new(Mutex::new(None));
+    tokio::spawn(write_ble(
+        current_device.clone(),
+        line_channel,
+        profile.tx_char_uuid,
+    ));
 
-        // Receive data from the Bluetooth LE device.
-        let chars = device.characteristics();
-        let rx_char = chars
-            .iter()
-            .find(|c| c.uuid == NORDIC_UART_RX_CHAR_UUID)
-            .ok_or("Unable to find RX characteric")?;
-        device.subscribe(&rx_char).await?;
-        let mut notification_stream = device.notifications().await?;
-        while let Some(data) = notification_stream.next().await {
-            let text = str::from_utf8(&data.value)?;
-            term.write_line(text.trim_end())?;
-            term.flush()?;
+    loop {
+        term.write_line(format!("Connecting to... {}", address).as_str())?;
+        let device = find_device_by_address(&adapter, &address).await?;
+        device.connect().await?;
+        if device.is_connected().await? {
+            term.write_line("Connected. Type quit() to exit.")?;
+            device.discover_services().await?;
+
+            let chars = device.characteristics();
+            let rx_char = chars
+                .iter()
+                .find(|c| c.uuid == profile.rx_char_uuid)
+                .ok_or("Unable to find RX characteric")?;
+            device.subscribe(&rx_char).await?;
+
+            *current_device.lock().await = Some(device.clone());
+
+            let device_id = device.id();
+            let mut events = adapter.events().await?;
+            let mut notification_stream = device.notifications().await?;
+            let mut line_buffer = LineBuffer::new();
+            loop {
+                tokio::select! {
+                    data = notification_stream.next() => {
+                        match data {
+                            Some(data) => {
+                                for line in line_buffer.push(&data.value) {
+                                    term.write_line(&line)?;
+                                    term.flush()?;
+                                }
+                            }
+                            None => break,
+                        }
+                    }
+                    Some(event) = events.next() => {
+                        if let CentralEvent::DeviceDisconnected(id) = event {
+                            if id == device_id {
+                                break;
+                            }
+                        }
+                    }
+                }
+            }
+
+            *current_device.lock().await = None;
+        }
+
+        // Reached whether the initial connection never came up or the
+        // session above ended in a mid-stream disconnect, so both cases get
+        // the same reconnect gating and backoff instead of one of them
+        // retrying immediately and unconditionally.
+        if !reconnect {
+            break;
         }
+        term.write_line("Device disconnected. Reconnecting...")?;
+        time::sleep(Duration::from_secs(retry_interval)).await;
     }
     Ok(())
 }
 
-/// Send data to Bluetooth LE device.
-async fn write_ble(device: Peripheral, text_channel: Receiver<String>) {
-    let chars = device.characteristics();
-    let tx_char = chars
-        .iter()
-        .find(|c| c.uuid == NORDIC_UART_TX_CHAR_UUID)
-        .ok_or("Unable to find TX characteric")
-        .unwrap();
+/// Send data typed on stdin to the currently connected Bluetooth LE device.
+///
+/// `device` is shared with the `repl` loop so that a reconnect can swap in a
+/// freshly connected peripheral without losing the stdin channel; while no
+/// device is connected, typed lines are simply held until one is available.
+async fn write_ble(
+    device: Arc<Mutex<Option<Peripheral>>>,
+    text_channel: Receiver<String>,
+    tx_char_uuid: Uuid,
+) {
     loop {
         let mut words = String::new();
         if let Ok(text) = text_channel.try_recv() {
@@ -218,16 +341,34 @@ async fn write_ble(device: Peripheral, text_channel: Receiver<String>) {
         }
         if !words.is_empty() {
             if words == "quit()" {
-                device
-                    .disconnect()
-                    .await
-                    .expect("Error disconnect from device.");
+                if let Some(device) = device.lock().await.as_ref() {
+                    device
+                        .disconnect()
+                        .await
+                        .expect("Error disconnect from device.");
+                }
                 std::process::exit(0);
             }
-            device
-                .write(&tx_char, words.as_bytes(), WriteType::WithoutResponse)
-                .await
-                .unwrap();
+            // Hold the line until `repl` has a connected device to write it to.
+            loop {
+                if let Some(device) = device.lock().await.as_ref() {
+                    let chars = device.characteristics();
+                    if let Some(tx_char) = chars.iter().find(|c| c.uuid == tx_char_uuid) {
+                        if let Err(err) = write_chunked(
+                            device,
+                            tx_char,
+                            words.as_bytes(),
+                            WriteType::WithoutResponse,
+                        )
+                        .await
+                        {
+                            log::error!("Error writing to device: {}", err);
+                        }
+                    }
+                    break;
+                }
+                time::sleep(Duration::from_millis(100)).await;
+            }
         }
         time::sleep(Duration::from_millis(100)).await;
     }
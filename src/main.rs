@@ -1,9 +1,14 @@
 mod ble;
+#[cfg(target_os = "linux")]
+mod server;
 mod utils;
 
 use std::error::Error;
 
 use clap::{Parser, Subcommand};
+use uuid::Uuid;
+
+use crate::ble::GattProfile;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -11,10 +16,33 @@ struct Cli {
     #[arg(long, default_value = "hci0")]
     adapter: String,
 
+    /// UUID of the serial-over-GATT service, e.g. to talk to a non-Nordic
+    /// UART profile such as meshtastic's.
+    #[arg(long, default_value = "6e400001-b5a3-f393-e0a9-e50e24dcca9e")]
+    service_uuid: Uuid,
+
+    /// UUID of the characteristic written to send data to the device.
+    #[arg(long, default_value = "6e400002-b5a3-f393-e0a9-e50e24dcca9e")]
+    tx_char_uuid: Uuid,
+
+    /// UUID of the characteristic subscribed to for data from the device.
+    #[arg(long, default_value = "6e400003-b5a3-f393-e0a9-e50e24dcca9e")]
+    rx_char_uuid: Uuid,
+
     #[command(subcommand)]
     command: Option<Commands>,
 }
 
+impl Cli {
+    fn gatt_profile(&self) -> GattProfile {
+        GattProfile {
+            service_uuid: self.service_uuid,
+            tx_char_uuid: self.tx_char_uuid,
+            rx_char_uuid: self.rx_char_uuid,
+        }
+    }
+}
+
 #[derive(Subcommand)]
 enum Commands {
     /// Scan to find Bluetooth LE devices.
@@ -36,6 +64,21 @@ enum Commands {
         /// device's address
         #[arg(long)]
         device: String,
+
+        /// automatically re-scan and reconnect if the device disconnects
+        #[arg(long)]
+        reconnect: bool,
+
+        /// seconds to wait before each reconnection attempt
+        #[arg(long, default_value_t = 5)]
+        retry_interval: u64,
+    },
+    /// Turn this machine into a Nordic UART GATT peripheral (Linux only).
+    #[cfg(target_os = "linux")]
+    Serve {
+        /// local name advertised to centrals
+        #[arg(long, default_value = "loranto")]
+        name: String,
     },
 }
 
@@ -45,9 +88,11 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     let cli = Cli::parse();
 
+    let profile = cli.gatt_profile();
+
     match &cli.command {
         Some(Commands::Scan { scan_time }) => {
-            let devices = ble::scan(cli.adapter, *scan_time).await?;
+            let devices = ble::scan(cli.adapter, *scan_time, profile).await?;
 
             for device in devices {
                 println!(
@@ -57,10 +102,25 @@ async fn main() -> Result<(), Box<dyn Error>> {
             }
         }
         Some(Commands::Send { device, text }) => {
-            ble::send(cli.adapter, device.clone(), text.join(" ")).await?;
+            ble::send(cli.adapter, device.clone(), text.join(" "), profile).await?;
+        }
+        Some(Commands::Repl {
+            device,
+            reconnect,
+            retry_interval,
+        }) => {
+            ble::repl(
+                cli.adapter,
+                device.clone(),
+                *reconnect,
+                *retry_interval,
+                profile,
+            )
+            .await?;
         }
-        Some(Commands::Repl { device }) => {
-            ble::repl(cli.adapter, device.clone()).await?;
+        #[cfg(target_os = "linux")]
+        Some(Commands::Serve { name }) => {
+            server::serve(cli.adapter, name.clone()).await?;
         }
         None => {}
     }
@@ -0,0 +1,127 @@
+// Copyright © 2023 Daniele Tricoli <eriol@mornie.org>
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Peripheral mode: advertise a Nordic UART GATT service and bridge it to
+//! stdin/stdout, turning this machine into a NUS peripheral instead of a
+//! central. `btleplug` is central-only, so this is built on `bluer`'s
+//! peripheral APIs and is only available on Linux.
+
+use std::error::Error;
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use bluer::adv::Advertisement;
+use bluer::gatt::local::{
+    Application, Characteristic, CharacteristicNotify, CharacteristicNotifyMethod,
+    CharacteristicWrite, CharacteristicWriteMethod, Service,
+};
+use futures::FutureExt;
+use tokio::time;
+
+use crate::ble::{NORDIC_UART_RX_CHAR_UUID, NORDIC_UART_SERVICE_UUID, NORDIC_UART_TX_CHAR_UUID};
+use crate::utils::{get_stdin_line_channel, mtu_chunks};
+
+pub async fn serve(adapter_name: String, local_name: String) -> Result<(), Box<dyn Error>> {
+    let session = bluer::Session::new().await?;
+    let adapter = session.adapter(&adapter_name)?;
+    adapter.set_powered(true).await?;
+
+    println!(
+        "Advertising {} as {} on {}",
+        NORDIC_UART_SERVICE_UUID,
+        local_name,
+        adapter.name()
+    );
+
+    let advertisement = Advertisement {
+        service_uuids: vec![NORDIC_UART_SERVICE_UUID].into_iter().collect(),
+        local_name: Some(local_name),
+        discoverable: Some(true),
+        ..Default::default()
+    };
+    let advertisement_handle = adapter.advertise(advertisement).await?;
+
+    // Lines typed on stdin are sent to subscribed centrals over the RX
+    // notify characteristic, mirroring the client side of `repl`. The
+    // notify method may be invoked again on re-subscribe, so the receiver
+    // is shared behind a mutex rather than moved in.
+    let line_channel = Arc::new(Mutex::new(get_stdin_line_channel()));
+
+    let app = Application {
+        services: vec![Service {
+            uuid: NORDIC_UART_SERVICE_UUID,
+            primary: true,
+            characteristics: vec![
+                Characteristic {
+                    uuid: NORDIC_UART_TX_CHAR_UUID,
+                    write: Some(CharacteristicWrite {
+                        write: true,
+                        write_without_response: true,
+                        method: CharacteristicWriteMethod::Fun(Box::new(move |new_value, _req| {
+                            async move {
+                                if let Ok(text) = std::str::from_utf8(&new_value) {
+                                    print!("{}", text);
+                                    io::stdout().flush().ok();
+                                }
+                                Ok(())
+                            }
+                            .boxed()
+                        })),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+                Characteristic {
+                    uuid: NORDIC_UART_RX_CHAR_UUID,
+                    notify: Some(CharacteristicNotify {
+                        notify: true,
+                        method: CharacteristicNotifyMethod::Fun(Box::new(move |mut notifier| {
+                            let line_channel = line_channel.clone();
+                            async move {
+                                loop {
+                                    let text = line_channel.lock().unwrap().try_recv().ok();
+                                    if let Some(text) = text {
+                                        // Fragment the same way `ble::write_chunked` does on
+                                        // the central side, so long lines aren't truncated by
+                                        // a subscriber's negotiated ATT MTU.
+                                        for chunk in mtu_chunks(text.as_bytes()) {
+                                            if let Err(err) = notifier.notify(chunk.to_vec()).await
+                                            {
+                                                // The subscriber is gone: stop this task
+                                                // instead of just this loop iteration, so a
+                                                // stale task doesn't keep stealing lines from
+                                                // `line_channel` out from under the next
+                                                // subscription's task.
+                                                log::error!(
+                                                    "Error notifying RX characteristic: {}",
+                                                    err
+                                                );
+                                                return;
+                                            }
+                                        }
+                                    }
+                                    time::sleep(Duration::from_millis(100)).await;
+                                }
+                            }
+                            .boxed()
+                        })),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        }],
+        ..Default::default()
+    };
+    let app_handle = adapter.serve_gatt_application(app).await?;
+
+    println!("Serving GATT application. Press Ctrl-C to stop.");
+    tokio::signal::ctrl_c().await?;
+
+    drop(app_handle);
+    drop(advertisement_handle);
+
+    Ok(())
+}
@@ -1,6 +1,9 @@
 use std::io;
+use std::str;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::mpsc;
 use std::sync::mpsc::Receiver;
+use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
 
@@ -8,11 +11,27 @@ use indicatif::{ProgressBar, ProgressStyle};
 use tokio::runtime;
 use tokio::time::interval;
 
-// Show a progress bar that will be full in `scan_time` seconds.
-pub fn progress_bar(scan_time: Duration) {
+// Neither btleplug nor bluer expose the negotiated ATT MTU, and many NUS
+// peripherals never negotiate past the default anyway, so assume it and
+// leave 3 bytes of headroom for the ATT opcode and handle.
+pub const DEFAULT_MTU: usize = 23;
+
+/// Split `data` into MTU-sized fragments so writes and notifications longer
+/// than the ATT MTU aren't silently truncated or rejected. Shared by the
+/// central (`ble::write_chunked`) and peripheral (`server`) sides, since the
+/// Nordic UART profile is a byte stream with no reassembly of its own on
+/// either end.
+pub fn mtu_chunks(data: &[u8]) -> std::slice::Chunks<'_, u8> {
+    data.chunks((DEFAULT_MTU - 3).max(1))
+}
+
+// Show a progress bar that will be full in `scan_time` seconds. `found`
+// is updated concurrently by the caller so the bar's message reflects the
+// number of devices discovered so far, not just elapsed time.
+pub fn progress_bar(scan_time: Duration, found: Arc<AtomicUsize>) {
     let steps = (scan_time.as_millis() / 5) as u64;
     let pb = ProgressBar::new(steps);
-    let spinner_style = ProgressStyle::with_template("{spinner} [{wide_bar}]")
+    let spinner_style = ProgressStyle::with_template("{spinner} [{wide_bar}] {msg}")
         .unwrap()
         .progress_chars("#>-");
     pb.set_style(spinner_style);
@@ -23,12 +42,12 @@ pub fn progress_bar(scan_time: Duration) {
         .expect("failed to create runtime");
 
     let future = async move {
-        pb.set_message("Scanning...");
         let mut intv = interval(Duration::from_millis(5));
 
         for _ in 0..steps {
             intv.tick().await;
             pb.inc(1);
+            pb.set_message(format!("Scanning... {} found", found.load(Ordering::Relaxed)));
         }
         pb.finish_with_message("Done");
     };
@@ -47,3 +66,34 @@ pub fn get_stdin_line_channel() -> Receiver<String> {
     });
     rx
 }
+
+/// Reassembles a stream of byte fragments, such as MTU-sized BLE
+/// notifications, into the complete lines they represent, since a single
+/// logical line routinely arrives split across several fragments (or
+/// several lines arrive coalesced into one).
+#[derive(Debug, Default)]
+pub struct LineBuffer {
+    buf: Vec<u8>,
+}
+
+impl LineBuffer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Feed in newly received bytes, returning the lines they complete.
+    /// Any bytes after the last `\n` are kept buffered for the next call.
+    /// A completed line that is not valid UTF-8 is dropped.
+    pub fn push(&mut self, data: &[u8]) -> Vec<String> {
+        self.buf.extend_from_slice(data);
+
+        let mut lines = Vec::new();
+        while let Some(pos) = self.buf.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.buf.drain(..=pos).collect();
+            if let Ok(text) = str::from_utf8(&line) {
+                lines.push(text.trim_end_matches(['\r', '\n']).to_string());
+            }
+        }
+        lines
+    }
+}